@@ -3,7 +3,7 @@
 //! Surface management for Android using the `GraphicBuffer` class and EGL.
 
 use crate::context::ContextID;
-use crate::egl::types::{EGLImageKHR, EGLSurface, EGLenum, EGLint};
+use crate::egl::types::{EGLImageKHR, EGLSurface, EGLSyncKHR, EGLenum, EGLint};
 use crate::gl::types::{GLenum, GLint, GLuint};
 use crate::platform::generic::egl::device::EGL_FUNCTIONS;
 use crate::platform::generic::egl::{EGLImageKHR, EGL_EXTENSION_FUNCTIONS};
@@ -14,21 +14,57 @@ use crate::{egl, gl};
 use super::context::{Context, GL_FUNCTIONS};
 use super::device::Device;
 use super::ffi::{AHARDWAREBUFFER_FORMAT_R8G8B8A8_UNORM, AHARDWAREBUFFER_USAGE_CPU_READ_NEVER};
-use super::ffi::{AHARDWAREBUFFER_USAGE_CPU_WRITE_NEVER, AHARDWAREBUFFER_USAGE_GPU_FRAMEBUFFER};
+use super::ffi::{AHARDWAREBUFFER_USAGE_CPU_READ_OFTEN, AHARDWAREBUFFER_USAGE_CPU_WRITE_NEVER};
+use super::ffi::{AHARDWAREBUFFER_USAGE_CPU_WRITE_OFTEN, AHARDWAREBUFFER_USAGE_GPU_FRAMEBUFFER};
+use super::ffi::{AHARDWAREBUFFER_FORMAT_R10G10B10A2_UNORM, AHARDWAREBUFFER_FORMAT_R16G16B16A16_FLOAT};
+use super::ffi::{AHARDWAREBUFFER_FORMAT_R5G6B5_UNORM, AHARDWAREBUFFER_FORMAT_R8G8B8X8_UNORM};
 use super::ffi::{AHARDWAREBUFFER_USAGE_GPU_SAMPLED_IMAGE, AHardwareBuffer, AHardwareBuffer_Desc};
-use super::ffi::{AHardwareBuffer_allocate, AHardwareBuffer_release, ANativeWindow};
+use super::ffi::{AHardwareBuffer_allocate, AHardwareBuffer_describe, AHardwareBuffer_lock};
+use super::ffi::{AHardwareBuffer_acquire, AHardwareBuffer_release, AHardwareBuffer_unlock};
+use super::ffi::ANativeWindow;
 use super::ffi::{ANativeWindow_getHeight, ANativeWindow_getWidth};
 
 use euclid::default::Size2D;
 use std::fmt::{self, Debug, Formatter};
 use std::marker::PhantomData;
-use std::os::raw::c_void;
+use std::mem;
+use std::os::raw::{c_int, c_void};
 use std::ptr;
+use std::slice;
 use std::thread;
 
-// FIXME(pcwalton): Is this right, or should it be `TEXTURE_EXTERNAL_OES`?
+// The texture target for surfaces this crate allocates. Surfaces imported via
+// `create_surface_from_hardware_buffer` use `TEXTURE_EXTERNAL_OES` instead, tracked per-surface
+// by `SurfaceObjects::HardwareBuffer::gl_texture_target`.
 const SURFACE_GL_TEXTURE_TARGET: GLenum = gl::TEXTURE_2D;
 
+/// The pixel format a generic surface's backing `AHardwareBuffer` is allocated in, chosen via
+/// `SurfaceAccess`'s sibling, the requesting context's pixel format attributes. Covers plain
+/// 8-bit RGBA alongside the HDR, wide-gamut, and packed formats real-world compositors need.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SurfaceFormat {
+    R8G8B8A8,
+    R8G8B8X8,
+    R5G6B5,
+    R16G16B16A16Float,
+    R10G10B10A2,
+}
+
+// Maps a `SurfaceFormat` to the `AHardwareBuffer_Desc.format` used to allocate the buffer, the
+// GL internal format the color attachment bound to its EGL image must match, and the number of
+// bytes each pixel occupies in the buffer's row stride.
+fn ahardware_buffer_and_gl_formats_for(format: SurfaceFormat) -> (u32, GLenum, usize) {
+    match format {
+        SurfaceFormat::R8G8B8A8 => (AHARDWAREBUFFER_FORMAT_R8G8B8A8_UNORM, gl::RGBA8, 4),
+        SurfaceFormat::R8G8B8X8 => (AHARDWAREBUFFER_FORMAT_R8G8B8X8_UNORM, gl::RGB8, 4),
+        SurfaceFormat::R5G6B5 => (AHARDWAREBUFFER_FORMAT_R5G6B5_UNORM, gl::RGB565, 2),
+        SurfaceFormat::R16G16B16A16Float => {
+            (AHARDWAREBUFFER_FORMAT_R16G16B16A16_FLOAT, gl::RGBA16F, 8)
+        }
+        SurfaceFormat::R10G10B10A2 => (AHARDWAREBUFFER_FORMAT_R10G10B10A2_UNORM, gl::RGB10_A2, 4),
+    }
+}
+
 pub struct Surface {
     pub(crate) context_id: ContextID,
     pub(crate) size: Size2D<i32>,
@@ -47,12 +83,37 @@ pub(crate) enum SurfaceObjects {
     HardwareBuffer {
         hardware_buffer: *mut AHardwareBuffer,
         egl_image: EGLImageKHR,
-        framebuffer_object: GLuint,
+        // `None` for surfaces imported via `create_surface_from_hardware_buffer`: their texture
+        // is bound as `GL_TEXTURE_EXTERNAL_OES`, which `OES_EGL_image_external` forbids
+        // attaching to a framebuffer, so there's no render target to back here.
+        framebuffer_object: Option<GLuint>,
         texture_object: GLuint,
-        renderbuffers: Renderbuffers,
+        renderbuffers: Option<Renderbuffers>,
+        // The GL internal format the color attachment (and `egl_image`) were allocated with,
+        // so that a `SurfaceTexture` created from this surface later binds it the same way.
+        gl_internal_format: GLenum,
+        // Bytes per pixel of `hardware_buffer`'s format, used to turn its row stride (in
+        // pixels) into a byte stride when locking it for CPU access. Zero for surfaces imported
+        // via `create_surface_from_hardware_buffer`, whose format isn't one of `SurfaceFormat`'s
+        // and so isn't known to be CPU-lockable.
+        bytes_per_pixel: usize,
+        // The GL texture target `egl_image` is bound to: `GL_TEXTURE_2D` for surfaces this
+        // crate allocated, or `GL_TEXTURE_EXTERNAL_OES` for ones imported from an externally
+        // supplied `AHardwareBuffer` (camera, `MediaCodec`, YUV producers) via
+        // `create_surface_from_hardware_buffer`, which must be sampled with
+        // `samplerExternalOES`.
+        gl_texture_target: GLenum,
+        // A fence the producer inserted after its last rendering command, so that a consumer
+        // sampling this surface's `AHardwareBuffer` from another context can wait for that
+        // rendering to complete instead of racing it. `egl::NO_SYNC_KHR` if no fence is pending.
+        sync: EGLSyncKHR,
     },
     Window {
         egl_surface: EGLSurface,
+        // The interval requested via `Device::set_swap_interval()`, reapplied before every
+        // `SwapBuffers()` since `eglSwapInterval()` applies to the current context, not the
+        // surface, and so needs to be restored if the surface is rebound to a different one.
+        swap_interval: i32,
     },
 }
 
@@ -74,48 +135,69 @@ impl Drop for Surface {
 
 pub struct NativeWidget {
     pub(crate) native_window: *mut ANativeWindow,
+    pub(crate) preserve_buffer_on_swap: bool,
 }
 
 impl Device {
     pub fn create_surface(&mut self,
                           context: &Context,
-                          _: SurfaceAccess,
+                          access: SurfaceAccess,
                           surface_type: &SurfaceType<NativeWidget>)
                           -> Result<Surface, Error> {
         match *surface_type {
-            SurfaceType::Generic { ref size } => self.create_generic_surface(context, size),
+            SurfaceType::Generic { ref size } => self.create_generic_surface(context, access, size),
             SurfaceType::Widget { ref native_widget } => {
                 unsafe {
-                    self.create_window_surface(context, native_widget.native_window)
+                    self.create_window_surface(context, native_widget)
                 }
             }
         }
     }
 
-    fn create_generic_surface(&mut self, context: &Context, size: &Size2D<i32>)
+    fn create_generic_surface(&mut self,
+                              context: &Context,
+                              access: SurfaceAccess,
+                              size: &Size2D<i32>)
                               -> Result<Surface, Error> {
         let _guard = self.temporarily_make_context_current(context)?;
 
+        // The color format the surface should be allocated in is driven by the context's
+        // attributes, not hardcoded, so that HDR/wide-gamut and packed-format workflows work.
+        let context_descriptor = self.context_descriptor(context);
+        let context_attributes = self.context_descriptor_attributes(&context_descriptor);
+        let surface_format = context_attributes.pixel_format;
+        let (hardware_buffer_format, gl_internal_format, bytes_per_pixel) =
+            ahardware_buffer_and_gl_formats_for(surface_format);
+
         GL_FUNCTIONS.with(|gl| {
             unsafe {
-                // Create a native hardware buffer.
+                // Create a native hardware buffer. Let the allocator pick the natural stride;
+                // only the CPU read/write usage bits depend on `access`, since the GPU always
+                // needs to be able to render to and sample from the buffer.
+                let cpu_usage = match access {
+                    SurfaceAccess::GPUOnly => {
+                        AHARDWAREBUFFER_USAGE_CPU_READ_NEVER | AHARDWAREBUFFER_USAGE_CPU_WRITE_NEVER
+                    }
+                    SurfaceAccess::GPUCPU | SurfaceAccess::GPUCPUWriteCombined => {
+                        AHARDWAREBUFFER_USAGE_CPU_READ_OFTEN | AHARDWAREBUFFER_USAGE_CPU_WRITE_OFTEN
+                    }
+                };
                 let hardware_buffer_desc = AHardwareBuffer_Desc {
-                    format: AHARDWAREBUFFER_FORMAT_R8G8B8A8_UNORM,
+                    format: hardware_buffer_format,
                     height: size.height as u32,
                     width: size.width as u32,
                     layers: 1,
                     rfu0: 0,
                     rfu1: 0,
-                    stride: 10,
-                    usage: AHARDWAREBUFFER_USAGE_CPU_READ_NEVER |
-                        AHARDWAREBUFFER_USAGE_CPU_WRITE_NEVER |
+                    stride: 0,
+                    usage: cpu_usage |
                         AHARDWAREBUFFER_USAGE_GPU_FRAMEBUFFER |
                         AHARDWAREBUFFER_USAGE_GPU_SAMPLED_IMAGE,
                 };
                 let mut hardware_buffer = ptr::null_mut();
                 let result = AHardwareBuffer_allocate(&hardware_buffer_desc, &mut hardware_buffer);
                 if result != 0 {
-                    return Err(Error::SurfaceCreationFailed(WindowingApiError::Failed));
+                    return Err(Error::UnsupportedSurfaceFormat(surface_format));
                 }
 
                 // Create an EGL image, and bind it to a texture.
@@ -123,7 +205,10 @@ impl Device {
 
                 // Initialize and bind the image to the texture.
                 let texture_object =
-                    generic::egl::surface::bind_egl_image_to_gl_texture(gl, egl_image);
+                    generic::egl::surface::bind_egl_image_to_gl_texture(gl,
+                                                                        egl_image,
+                                                                        SURFACE_GL_TEXTURE_TARGET,
+                                                                        gl_internal_format);
 
                 // Create the framebuffer, and bind the texture to it.
                 let framebuffer_object =
@@ -131,10 +216,9 @@ impl Device {
                                                           SURFACE_GL_TEXTURE_TARGET,
                                                           texture_object);
 
-                // Bind renderbuffers as appropriate.
-                let context_descriptor = self.context_descriptor(context);
-                let context_attributes = self.context_descriptor_attributes(&context_descriptor);
-                let renderbuffers = Renderbuffers::new(gl, size, &context_attributes);
+                // Bind renderbuffers as appropriate, matching the color attachment's format.
+                let renderbuffers =
+                    Renderbuffers::new(gl, size, &context_attributes, gl_internal_format);
                 renderbuffers.bind_to_current_framebuffer(gl);
 
                 debug_assert_eq!(gl.CheckFramebufferStatus(gl::FRAMEBUFFER),
@@ -146,9 +230,13 @@ impl Device {
                     objects: SurfaceObjects::HardwareBuffer {
                         hardware_buffer,
                         egl_image,
-                        framebuffer_object,
+                        framebuffer_object: Some(framebuffer_object),
                         texture_object,
-                        renderbuffers,
+                        renderbuffers: Some(renderbuffers),
+                        gl_internal_format,
+                        bytes_per_pixel,
+                        gl_texture_target: SURFACE_GL_TEXTURE_TARGET,
+                        sync: egl::NO_SYNC_KHR,
                     },
                     destroyed: false,
                 })
@@ -158,49 +246,84 @@ impl Device {
 
     unsafe fn create_window_surface(&mut self,
                                     context: &Context,
-                                    native_window: *mut ANativeWindow)
+                                    native_widget: &NativeWidget)
                                     -> Result<Surface, Error> {
+        let native_window = native_widget.native_window;
         let width = ANativeWindow_getWidth(native_window);
         let height = ANativeWindow_getHeight(native_window);
 
         let context_descriptor = self.context_descriptor(context);
         let egl_config = self.context_descriptor_to_egl_config(&context_descriptor);
+        let egl_display = self.native_display.egl_display();
 
-        let egl_surface = EGL_FUNCTIONS::CreateWindowSurface(self.native_display.egl_display(),
+        let egl_surface = EGL_FUNCTIONS::CreateWindowSurface(egl_display,
                                                              egl_config,
                                                              native_window as *const c_void,
                                                              ptr::null());
         assert_ne!(egl_surface, egl::NO_SURFACE);
 
+        if native_widget.preserve_buffer_on_swap {
+            EGL_FUNCTIONS.with(|egl| {
+                let mut surface_type = 0;
+                egl.GetConfigAttrib(egl_display,
+                                   egl_config,
+                                   egl::SURFACE_TYPE as EGLint,
+                                   &mut surface_type);
+                if surface_type as EGLenum & egl::SWAP_BEHAVIOR_PRESERVED_BIT == 0 {
+                    egl.DestroySurface(egl_display, egl_surface);
+                    return Err(Error::UnsupportedSwapBehavior);
+                }
+
+                let ok = egl.SurfaceAttrib(egl_display,
+                                          egl_surface,
+                                          egl::SWAP_BEHAVIOR as EGLint,
+                                          egl::BUFFER_PRESERVED as EGLint);
+                assert_ne!(ok, egl::FALSE);
+                Ok(())
+            })?;
+        }
+
         Ok(Surface {
             context_id: context.id,
             size: Size2D::new(width, height),
-            objects: SurfaceObjects::Window { egl_surface },
+            objects: SurfaceObjects::Window { egl_surface, swap_interval: 1 },
             destroyed: false,
         })
     }
 
-    pub fn create_surface_texture(&self, context: &mut Context, surface: Surface)
+    pub fn create_surface_texture(&self, context: &mut Context, mut surface: Surface)
                                   -> Result<SurfaceTexture, Error> {
         unsafe {
-            match surface.objects {
+            let (hardware_buffer, gl_internal_format, gl_texture_target) = match surface.objects {
                 SurfaceObjects::Window { .. } => return Err(Error::WidgetAttached),
-                SurfaceObjects::HardwareBuffer { hardware_buffer, .. } => {
-                    GL_FUNCTIONS.with(|gl| {
-                        let _guard = self.temporarily_make_context_current(context)?;
-                        let local_egl_image = self.create_egl_image(context, hardware_buffer);
-                        let texture_object = generic::egl::surface::bind_egl_image_to_gl_texture(
-                            gl,
-                            local_egl_image);
-                        Ok(SurfaceTexture {
-                            surface,
-                            local_egl_image,
-                            texture_object,
-                            phantom: PhantomData,
-                        })
-                    })
-                }
-            }
+                SurfaceObjects::HardwareBuffer {
+                    hardware_buffer,
+                    gl_internal_format,
+                    gl_texture_target,
+                    ..
+                } => (hardware_buffer, gl_internal_format, gl_texture_target),
+            };
+
+            GL_FUNCTIONS.with(|gl| {
+                let _guard = self.temporarily_make_context_current(context)?;
+
+                // `eglWaitSyncKHR` waits in whatever context is current on this thread, so it
+                // must run after the context switch above, not before it.
+                self.wait_for_hardware_buffer_fence(&mut surface);
+
+                let local_egl_image = self.create_egl_image(context, hardware_buffer);
+                let texture_object = generic::egl::surface::bind_egl_image_to_gl_texture(
+                    gl,
+                    local_egl_image,
+                    gl_texture_target,
+                    gl_internal_format);
+                Ok(SurfaceTexture {
+                    surface,
+                    local_egl_image,
+                    texture_object,
+                    phantom: PhantomData,
+                })
+            })
         }
     }
 
@@ -213,8 +336,10 @@ impl Device {
         EGL_FUNCTIONS.with(|egl| {
             unsafe {
                 match surface.objects {
-                    SurfaceObjects::Window { egl_surface } => {
-                        egl.SwapBuffers(self.native_display.egl_display(), egl_surface);
+                    SurfaceObjects::Window { egl_surface, swap_interval } => {
+                        let egl_display = self.native_display.egl_display();
+                        egl.SwapInterval(egl_display, swap_interval as EGLint);
+                        egl.SwapBuffers(egl_display, egl_surface);
                         Ok(())
                     }
                     SurfaceObjects::HardwareBuffer { .. } => Err(Error::NoWidgetAttached),
@@ -223,6 +348,86 @@ impl Device {
         })
     }
 
+    // Sets the number of vsyncs `present_surface` waits for before presenting (`0` disables
+    // throttling). Stored on the surface and reapplied on every present, since
+    // `eglSwapInterval()` applies to the current context, not the surface.
+    pub fn set_swap_interval(&self, context: &Context, surface: &mut Surface, interval: i32)
+                             -> Result<(), Error> {
+        let _guard = self.temporarily_make_context_current(context)?;
+
+        let swap_interval_slot = match surface.objects {
+            SurfaceObjects::HardwareBuffer { .. } => return Err(Error::NoWidgetAttached),
+            SurfaceObjects::Window { ref mut swap_interval, .. } => swap_interval,
+        };
+
+        EGL_FUNCTIONS.with(|egl| {
+            unsafe {
+                egl.SwapInterval(self.native_display.egl_display(), interval as EGLint);
+            }
+        });
+
+        *swap_interval_slot = interval;
+        Ok(())
+    }
+
+    // Inserts a GPU fence after the producer's rendering commands, so a consumer in another
+    // context (or, via `native_fence_fd`, another process) can wait for it instead of racing it.
+    // Makes `context` current for the duration of the call, so the fence follows its commands.
+    pub fn sync_surface(&self, context: &Context, surface: &mut Surface) -> Result<(), Error> {
+        let _guard = self.temporarily_make_context_current(context)?;
+
+        let sync_slot = match surface.objects {
+            SurfaceObjects::Window { .. } => return Err(Error::NoWidgetAttached),
+            SurfaceObjects::HardwareBuffer { ref mut sync, .. } => sync,
+        };
+
+        unsafe {
+            let egl_display = self.native_display.egl_display();
+            // `eglDupNativeFenceFDANDROID` only accepts native fence syncs, so create one of
+            // those where the driver supports them, so that `native_fence_fd` can export it.
+            let sync_type = if EGL_EXTENSION_FUNCTIONS.DupNativeFenceFDANDROID.is_some() {
+                egl::SYNC_NATIVE_FENCE_ANDROID
+            } else {
+                egl::SYNC_FENCE_KHR
+            };
+            let new_sync = (EGL_EXTENSION_FUNCTIONS.CreateSyncKHR)(egl_display,
+                                                                   sync_type as EGLenum,
+                                                                   ptr::null());
+            assert_ne!(new_sync, egl::NO_SYNC_KHR);
+
+            // The fence command itself isn't guaranteed to reach the GPU until a flush after
+            // it's created, so this must come after CreateSyncKHR, not before.
+            GL_FUNCTIONS.with(|gl| gl.Flush());
+
+            if *sync_slot != egl::NO_SYNC_KHR {
+                (EGL_EXTENSION_FUNCTIONS.DestroySyncKHR)(egl_display, *sync_slot);
+            }
+            *sync_slot = new_sync;
+        }
+
+        Ok(())
+    }
+
+    // Waits on (and retires) any fence the producer left on this surface, so that this context
+    // can safely sample it. No-op for surfaces without a pending fence, and for windows, which
+    // have no cross-context sharing story.
+    unsafe fn wait_for_hardware_buffer_fence(&self, surface: &mut Surface) {
+        let sync_slot = match surface.objects {
+            SurfaceObjects::Window { .. } => return,
+            SurfaceObjects::HardwareBuffer { ref mut sync, .. } => sync,
+        };
+        if *sync_slot == egl::NO_SYNC_KHR {
+            return;
+        }
+
+        let egl_display = self.native_display.egl_display();
+        let wait_result = (EGL_EXTENSION_FUNCTIONS.WaitSyncKHR)(egl_display, *sync_slot, 0);
+        assert_ne!(wait_result, egl::FALSE);
+
+        (EGL_EXTENSION_FUNCTIONS.DestroySyncKHR)(egl_display, *sync_slot);
+        *sync_slot = egl::NO_SYNC_KHR;
+    }
+
     unsafe fn create_egl_image(&self, _: &Context, hardware_buffer: *mut AHardwareBuffer)
                                -> EGLImageKHR {
         // Get the native client buffer.
@@ -263,12 +468,17 @@ impl Device {
                     ref mut framebuffer_object,
                     ref mut texture_object,
                     ref mut renderbuffers,
+                    ref mut sync,
+                    ..
                 } => {
                     GL_FUNCTIONS.with(|gl| {
-                        gl.BindFramebuffer(gl::FRAMEBUFFER, 0);
-                        gl.DeleteFramebuffers(1, framebuffer_object);
-                        *framebuffer_object = 0;
-                        renderbuffers.destroy(gl);
+                        if let Some(mut framebuffer_object) = framebuffer_object.take() {
+                            gl.BindFramebuffer(gl::FRAMEBUFFER, 0);
+                            gl.DeleteFramebuffers(1, &mut framebuffer_object);
+                        }
+                        if let Some(mut renderbuffers) = renderbuffers.take() {
+                            renderbuffers.destroy(gl);
+                        }
 
                         gl.DeleteTextures(1, texture_object);
                         *texture_object = 0;
@@ -279,11 +489,16 @@ impl Device {
                         assert_ne!(result, egl::FALSE);
                         *egl_image = EGL_NO_IMAGE_KHR;
 
+                        if *sync != egl::NO_SYNC_KHR {
+                            (EGL_EXTENSION_FUNCTIONS.DestroySyncKHR)(egl_display, *sync);
+                            *sync = egl::NO_SYNC_KHR;
+                        }
+
                         AHardwareBuffer_release(*hardware_buffer);
                         *hardware_buffer = ptr::null_mut();
                     });
                 }
-                SurfaceObjects::Window { ref mut egl_surface } => {
+                SurfaceObjects::Window { ref mut egl_surface, .. } => {
                     EGL_FUNCTIONS.with(|egl| {
                         egl.DestroySurface(self.native_display.egl_display(), *egl_surface);
                         *egl_surface = egl::NO_SURFACE;
@@ -318,22 +533,118 @@ impl Device {
         })
     }
 
-    #[inline]
     pub fn lock_surface_data<'s>(&self, surface: &'s mut Surface)
                                  -> Result<SurfaceDataGuard<'s>, Error> {
-        Err(Error::Unimplemented)
+        let (hardware_buffer, bytes_per_pixel) = match surface.objects {
+            SurfaceObjects::Window { .. } => return Err(Error::WidgetAttached),
+            SurfaceObjects::HardwareBuffer { hardware_buffer, bytes_per_pixel, .. } => {
+                (hardware_buffer, bytes_per_pixel)
+            }
+        };
+        if bytes_per_pixel == 0 {
+            return Err(Error::SurfaceDataInaccessible(WindowingApiError::Failed));
+        }
+
+        unsafe {
+            let mut hardware_buffer_desc = mem::zeroed();
+            AHardwareBuffer_describe(hardware_buffer, &mut hardware_buffer_desc);
+
+            // `AHardwareBuffer_lock` only accepts CPU_READ_*/CPU_WRITE_* usage bits, so the GPU
+            // usage the buffer was allocated with must be masked out rather than forwarded.
+            let cpu_usage =
+                hardware_buffer_desc.usage &
+                (AHARDWAREBUFFER_USAGE_CPU_READ_OFTEN | AHARDWAREBUFFER_USAGE_CPU_WRITE_OFTEN);
+
+            let mut data_ptr = ptr::null_mut();
+            let result = AHardwareBuffer_lock(hardware_buffer,
+                                              cpu_usage,
+                                              -1,
+                                              ptr::null(),
+                                              &mut data_ptr);
+            if result != 0 {
+                return Err(Error::SurfaceDataInaccessible(WindowingApiError::Failed));
+            }
+
+            Ok(SurfaceDataGuard {
+                phantom: PhantomData,
+                hardware_buffer,
+                data_ptr: data_ptr as *mut u8,
+                stride: hardware_buffer_desc.stride as usize * bytes_per_pixel,
+                size: surface.size,
+            })
+        }
     }
 
+    // The GL texture target `surface`'s backing `egl_image` is bound to: `GL_TEXTURE_2D`, or
+    // `GL_TEXTURE_EXTERNAL_OES` for surfaces imported via `create_surface_from_hardware_buffer`.
     #[inline]
-    pub fn surface_gl_texture_target(&self) -> GLenum {
-        SURFACE_GL_TEXTURE_TARGET
+    pub fn surface_gl_texture_target(&self, surface: &Surface) -> GLenum {
+        match surface.objects {
+            SurfaceObjects::HardwareBuffer { gl_texture_target, .. } => gl_texture_target,
+            SurfaceObjects::Window { .. } => SURFACE_GL_TEXTURE_TARGET,
+        }
+    }
+
+    // Imports an `AHardwareBuffer` from an external producer (camera, `MediaCodec`, YUV) as a
+    // `Surface`, bound as `GL_TEXTURE_EXTERNAL_OES`. Takes its own reference via
+    // `AHardwareBuffer_acquire`; the caller keeps ownership of theirs.
+    pub unsafe fn create_surface_from_hardware_buffer(&mut self,
+                                                      context: &Context,
+                                                      hardware_buffer: *mut AHardwareBuffer)
+                                                      -> Result<Surface, Error> {
+        let _guard = self.temporarily_make_context_current(context)?;
+
+        AHardwareBuffer_acquire(hardware_buffer);
+
+        let mut hardware_buffer_desc = mem::zeroed();
+        AHardwareBuffer_describe(hardware_buffer, &mut hardware_buffer_desc);
+        let size = Size2D::new(hardware_buffer_desc.width as i32,
+                               hardware_buffer_desc.height as i32);
+
+        GL_FUNCTIONS.with(|gl| {
+            let egl_image = self.create_egl_image(context, hardware_buffer);
+            let texture_object = generic::egl::surface::bind_egl_image_to_gl_texture(
+                gl,
+                egl_image,
+                gl::TEXTURE_EXTERNAL_OES,
+                gl::NONE);
+
+            // `GL_TEXTURE_EXTERNAL_OES` textures can't be attached to a framebuffer
+            // (`OES_EGL_image_external`), and this surface only ever needs to be sampled, so
+            // there's no framebuffer or renderbuffers to set up here.
+
+            Ok(Surface {
+                size,
+                context_id: context.id,
+                objects: SurfaceObjects::HardwareBuffer {
+                    hardware_buffer,
+                    egl_image,
+                    framebuffer_object: None,
+                    texture_object,
+                    renderbuffers: None,
+                    gl_internal_format: gl::NONE,
+                    bytes_per_pixel: 0,
+                    gl_texture_target: gl::TEXTURE_EXTERNAL_OES,
+                    sync: egl::NO_SYNC_KHR,
+                },
+                destroyed: false,
+            })
+        })
     }
 }
 
 impl NativeWidget {
     #[inline]
     pub unsafe fn from_native_window(native_window: *mut ANativeWindow) -> NativeWidget {
-        NativeWidget { native_window }
+        NativeWidget { native_window, preserve_buffer_on_swap: false }
+    }
+
+    // Like `from_native_window`, but requests `EGL_SWAP_BEHAVIOR_PRESERVED_BIT` so
+    // `present_surface` doesn't discard the back buffer's contents.
+    #[inline]
+    pub unsafe fn from_native_window_with_preserved_buffer(native_window: *mut ANativeWindow)
+                                                            -> NativeWidget {
+        NativeWidget { native_window, preserve_buffer_on_swap: true }
     }
 }
 
@@ -346,7 +657,7 @@ impl Surface {
     pub fn id(&self) -> SurfaceID {
         match self.objects {
             SurfaceObjects::HardwareBuffer { egl_image, .. } => SurfaceID(egl_image as usize),
-            SurfaceObjects::Window { egl_surface } => SurfaceID(egl_surface as usize),
+            SurfaceObjects::Window { egl_surface, .. } => SurfaceID(egl_surface as usize),
         }
     }
 
@@ -354,6 +665,51 @@ impl Surface {
     pub fn context_id(&self) -> ContextID {
         self.context_id
     }
+
+    // How many frames old the back buffer's contents are, via `EGL_EXT_buffer_age` (`0` means
+    // undefined). `None` without a window or `EGL_EXT_buffer_age` support.
+    pub fn buffer_age(&self, device: &Device) -> Option<usize> {
+        let egl_surface = match self.objects {
+            SurfaceObjects::HardwareBuffer { .. } => return None,
+            SurfaceObjects::Window { egl_surface, .. } => egl_surface,
+        };
+
+        EGL_FUNCTIONS.with(|egl| {
+            unsafe {
+                let mut age: EGLint = 0;
+                let ok = egl.QuerySurface(device.native_display.egl_display(),
+                                         egl_surface,
+                                         egl::BUFFER_AGE_EXT as EGLint,
+                                         &mut age);
+                if ok == egl::FALSE {
+                    return None;
+                }
+                Some(age as usize)
+            }
+        })
+    }
+
+    // Exports the pending producer fence (see `Device::sync_surface`) as a native fence FD via
+    // `EGL_ANDROID_native_fence_sync`. `None` if there's no pending fence or the driver lacks it.
+    pub fn native_fence_fd(&self, device: &Device) -> Option<c_int> {
+        let sync = match self.objects {
+            SurfaceObjects::Window { .. } => return None,
+            SurfaceObjects::HardwareBuffer { sync, .. } => sync,
+        };
+        if sync == egl::NO_SYNC_KHR {
+            return None;
+        }
+
+        unsafe {
+            let dup_native_fence_fd = EGL_EXTENSION_FUNCTIONS.DupNativeFenceFDANDROID?;
+            let fd = dup_native_fence_fd(device.native_display.egl_display(), sync);
+            if fd == egl::NO_NATIVE_FENCE_FD_ANDROID {
+                None
+            } else {
+                Some(fd)
+            }
+        }
+    }
 }
 
 impl SurfaceTexture {
@@ -361,8 +717,51 @@ impl SurfaceTexture {
     pub fn gl_texture(&self) -> GLuint {
         self.texture_object
     }
+
+    // The GL texture target `gl_texture()` must be bound/sampled with; `GL_TEXTURE_EXTERNAL_OES`
+    // if the surface was imported via `Device::create_surface_from_hardware_buffer`.
+    #[inline]
+    pub fn gl_texture_target(&self) -> GLenum {
+        match self.surface.objects {
+            SurfaceObjects::HardwareBuffer { gl_texture_target, .. } => gl_texture_target,
+            SurfaceObjects::Window { .. } => SURFACE_GL_TEXTURE_TARGET,
+        }
+    }
 }
 
 pub struct SurfaceDataGuard<'a> {
-    phantom: PhantomData<&'a ()>,
+    phantom: PhantomData<&'a mut Surface>,
+    hardware_buffer: *mut AHardwareBuffer,
+    data_ptr: *mut u8,
+    stride: usize,
+    size: Size2D<i32>,
+}
+
+impl<'a> Drop for SurfaceDataGuard<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            AHardwareBuffer_unlock(self.hardware_buffer, ptr::null_mut());
+        }
+    }
+}
+
+impl<'a> SurfaceDataGuard<'a> {
+    #[inline]
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.data_ptr, self.stride * self.size.height as usize)
+        }
+    }
+
+    #[inline]
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.data_ptr, self.stride * self.size.height as usize)
+        }
+    }
 }